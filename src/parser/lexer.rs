@@ -1,5 +1,8 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
 use parser::combinators::{Parser, StrParser, ParseTo, Consumer, string, character};
-use self::Token::{LParen, RParen, Whitespace, Identifier};
+use self::Token::{LParen, RParen, Whitespace, Identifier, Number, StringLit};
 
 #[derive(Copy, Clone, Eq, Hash, Ord, PartialOrd, Debug)]
 pub enum Token<'a> {
@@ -7,6 +10,8 @@ pub enum Token<'a> {
     RParen,
     Whitespace,
     Identifier(&'a str),
+    Number(&'a str),
+    StringLit(&'a str),
 }
 
 impl<'a,'b> PartialEq<Token<'b>> for Token<'a> {
@@ -16,6 +21,8 @@ impl<'a,'b> PartialEq<Token<'b>> for Token<'a> {
             (RParen, RParen)                       => true,
             (Whitespace, Whitespace)               => true,
             (Identifier(ref x), Identifier(ref y)) => x == y,
+            (Number(ref x), Number(ref y))         => x == y,
+            (StringLit(ref x), StringLit(ref y))   => x == y,
             _                                      => false
         }
     }
@@ -28,6 +35,8 @@ impl<'a> From<Token<'a>> for String {
             RParen => ")",
             Whitespace => "<space>",
             Identifier(x) => x,
+            Number(x) => x,
+            StringLit(x) => x,
         })
     }
 }
@@ -38,17 +47,96 @@ pub trait LexerConsumer<D> where D: for<'a> Consumer<Token<'a>> {
 
 fn mk_identifier<'a>(s: &'a str) -> Token<'a> { Identifier(s) }
 
+fn mk_number<'a>(s: &'a str) -> Token<'a> { Number(s) }
+
+// The buffered slice still carries the surrounding double quotes; keep only the
+// quoted contents in the token.
+fn mk_string<'a>(s: &'a str) -> Token<'a> { StringLit(&s[1..s.len() - 1]) }
+
+// A keyword or symbolic identifier: `i32.add`, `get_local`, `$counter`. Starts
+// with a letter or `$` and continues with the WAT "id char" set.
+fn is_identifier_start(c: char) -> bool { c.is_alphabetic() || c == '$' }
+fn is_identifier_char(c: char) -> bool { c.is_alphanumeric() || c == '.' || c == '_' || c == '$' }
+
+// A numeric literal: an optional sign, then digits that may be `0x` hex or a
+// float with a `.` fraction and `e`/`e-` exponent. The continuation set is kept
+// loose (alphanumerics plus `. + -`) so the single token captures all of these
+// shapes; the parser validates the exact format when it reads the value.
+fn is_number_start(c: char) -> bool { c.is_digit(10) || c == '+' || c == '-' }
+fn is_number_char(c: char) -> bool { c.is_alphanumeric() || c == '.' || c == '+' || c == '-' }
+
 #[allow(non_snake_case)]
 pub fn lexer<C,D>(consumer: C) where C: LexerConsumer<D>, D: for<'a> Consumer<Token<'a>> {
+    // Comments are skipped by lexing them as `Whitespace`. The block-comment
+    // parser is tried before `(` so that `(;` opens a comment rather than a
+    // lone left paren; likewise `;;` is tried before any bare `;`.
+    let BLOCK_COMMENT = block_comment().map(|_| Whitespace);
+    let LINE_COMMENT = string(";;").and_then(character(|c| c != '\n').star()).map(|_| Whitespace);
     let LPAREN = string("(").map(|_| LParen);
     let RPAREN = string(")").map(|_| RParen);
     let WHITESPACE = character(char::is_whitespace).map(|_| Whitespace);
-    let IDENTIFIER = character(char::is_alphabetic).and_then(character(char::is_alphanumeric).star())
+    let IDENTIFIER = character(is_identifier_start).and_then(character(is_identifier_char).star())
                                                    .buffer().map(mk_identifier);
-    let TOKEN = LPAREN.or_else(RPAREN).or_else(WHITESPACE).or_else(IDENTIFIER);
+    let NUMBER = character(is_number_start).and_then(character(is_number_char).star())
+                                           .buffer().map(mk_number);
+    let STRING = string("\"").and_then(string_char().star()).and_then(string("\""))
+                             .buffer().map(mk_string);
+    let TOKEN = BLOCK_COMMENT.or_else(LINE_COMMENT)
+                             .or_else(LPAREN).or_else(RPAREN).or_else(WHITESPACE)
+                             .or_else(NUMBER).or_else(STRING).or_else(IDENTIFIER);
     consumer.accept(TOKEN.star())
 }
 
+// A single character of a string literal: either an escape sequence
+// (`\t`, `\n`, `\"`, `\\`, `\HH`) consumed as a backslash plus its following
+// byte, or any ordinary character that is neither the closing quote nor a
+// backslash. For the `\HH` hex form the escape branch takes the first digit
+// and the second is read as an ordinary character; only the delimiters matter
+// for tokenization.
+fn string_char() -> impl for<'a> StrParser<'a> {
+    let escape = character(|c| c == '\\').and_then(character(|_| true));
+    let normal = character(|c| c != '"' && c != '\\');
+    escape.or_else(normal)
+}
+
+// A `(; … ;)` block comment. The opening `(;` resets a shared depth counter to
+// `1`; the body then consumes one character at a time, incrementing the depth on
+// every nested `(;` and decrementing it on every `;)`, so inner comment pairs
+// balance before the outer close is reached. Lone `;` and `(` inside the body
+// are ordinary characters, so `(; a ; b ;)` and `(; outer (; inner ;) outer ;)`
+// both lex as a single comment. The body `star` stops just before the final
+// `)` that drops the depth back to `0`; the trailing `string(")")` consumes it.
+// Lexing is sequential, so re-using the one counter across comments is sound.
+fn block_comment() -> impl for<'a> StrParser<'a> {
+    let depth = Rc::new(Cell::new(0i32));
+    let prev = Rc::new(Cell::new('\0'));
+
+    let (open_depth, open_prev) = (depth.clone(), prev.clone());
+    let open = string("(;").map(move |s| {
+        open_depth.set(1);
+        open_prev.set('\0');
+        s
+    });
+
+    let (body_depth, body_prev) = (depth.clone(), prev.clone());
+    let body = character(move |c| {
+        match (body_prev.get(), c) {
+            ('(', ';') => {
+                body_depth.set(body_depth.get() + 1);
+                body_prev.set('\0');
+            },
+            (';', ')') => {
+                body_depth.set(body_depth.get() - 1);
+                body_prev.set('\0');
+            },
+            _ => body_prev.set(c),
+        }
+        body_depth.get() > 0
+    }).star();
+
+    open.and_then(body).and_then(string(")")).buffer()
+}
+
 #[test]
 fn test_lexer() {
     struct TestConsumer(Vec<String>);
@@ -79,3 +167,56 @@ fn test_partial_eq() {
     bar(Identifier("hi"),Identifier(&*hi));
     bar(Identifier(&*hi),Identifier("hi"));
 }
+
+#[test]
+fn test_lexer_numbers() {
+    struct TestConsumer(Vec<String>);
+    impl<'a> Consumer<Token<'a>> for TestConsumer {
+        fn accept(&mut self, tok: Token<'a>) {
+            self.0.push(String::from(tok));
+        }
+    }
+    impl LexerConsumer<TestConsumer> for TestConsumer {
+        fn accept<L>(mut self, mut lex: L) where L: for<'a> ParseTo<&'a str,TestConsumer> {
+            lex.push_to("42 0x1f 3.5e2", &mut self);
+            assert_eq!(self.0, vec!["42", "<space>", "0x1f", "<space>", "3.5e2"]);
+        }
+    }
+    lexer(TestConsumer(Vec::new()));
+}
+
+#[test]
+fn test_lexer_dollar_identifier() {
+    struct TestConsumer(Vec<String>);
+    impl<'a> Consumer<Token<'a>> for TestConsumer {
+        fn accept(&mut self, tok: Token<'a>) {
+            self.0.push(String::from(tok));
+        }
+    }
+    impl LexerConsumer<TestConsumer> for TestConsumer {
+        fn accept<L>(mut self, mut lex: L) where L: for<'a> ParseTo<&'a str,TestConsumer> {
+            lex.push_to("(get_local $counter)", &mut self);
+            assert_eq!(self.0, vec!["(", "get_local", "<space>", "$counter", ")"]);
+        }
+    }
+    lexer(TestConsumer(Vec::new()));
+}
+
+#[test]
+fn test_lexer_nested_block_comment() {
+    struct TestConsumer(Vec<String>);
+    impl<'a> Consumer<Token<'a>> for TestConsumer {
+        fn accept(&mut self, tok: Token<'a>) {
+            self.0.push(String::from(tok));
+        }
+    }
+    impl LexerConsumer<TestConsumer> for TestConsumer {
+        fn accept<L>(mut self, mut lex: L) where L: for<'a> ParseTo<&'a str,TestConsumer> {
+            // The whole nested comment lexes as a single `Whitespace`; the inner
+            // `(;`/`;)` pair must balance before the outer close.
+            lex.push_to("(; a (; b ;) c ;)7", &mut self);
+            assert_eq!(self.0, vec!["<space>", "7"]);
+        }
+    }
+    lexer(TestConsumer(Vec::new()));
+}
@@ -0,0 +1,385 @@
+//! Recursive-descent parser for the WebAssembly text format.
+//!
+//! The [`lexer`](::parser::lexer) turns source text into a `Token` stream but
+//! nothing consumes it. This module drives the lexer, drops the insignificant
+//! `Whitespace` tokens, and walks the remaining `LParen`/`RParen`/`Identifier`/
+//! `Number` stream as nested S-expressions, dispatching on the leading keyword
+//! after each `(` to build `wasm_ast::Expr` nodes.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use parser::combinators::{Consumer, ParseTo};
+use parser::lexer::{lexer, LexerConsumer, Token};
+use parser::lexer::Token::{Identifier, LParen, Number, RParen, Whitespace};
+
+use wasm_ast::{BinOp, Const, Expr, Typ, UnaryOp, Var};
+use wasm_ast::BinOp::{Add, And, DivS, DivU, Eq, GeS, GeU, GtS, GtU, LeS, LeU, LtS, LtU};
+use wasm_ast::BinOp::{Mul, Ne, Or, RemS, RemU, RotL, RotR, Shl, ShrS, ShrU, Sub, Xor};
+use wasm_ast::Const::{F32Const, F64Const, I32Const, I64Const};
+use wasm_ast::Expr::{BinOpExpr, BlockExpr, BrExpr, BrIfExpr, ConstExpr, GetLocalExpr, GrowMemoryExpr};
+use wasm_ast::Expr::{IfThenExpr, IfThenElseExpr, LoadExpr, LoopExpr, NopExpr, ReturnExpr, SetLocalExpr, StoreExpr, UnaryOpExpr};
+use wasm_ast::Typ::{F32, F64, I32, I64};
+use wasm_ast::UnaryOp::{Clz, Ctz, Eqz, Popcnt};
+
+/// A failure encountered while parsing WAT source.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ParseError {
+    /// A token turned up where a different one was required.
+    UnexpectedToken(String),
+    /// The leading keyword of an S-expression is not a known instruction.
+    UnknownKeyword(String),
+    /// A numeric literal could not be parsed as the expected type.
+    MalformedNumber(String),
+    /// The token stream ended in the middle of an expression.
+    UnexpectedEof,
+}
+
+/// Parse a single WAT expression from source text.
+pub fn parse(source: &str) -> Result<Expr, ParseError> {
+    let tokens = tokenize(source);
+    let mut parser = Parser { tokens: &tokens, pos: 0, locals: Vec::new() };
+    let expr = parser.parse_expr()?;
+    match parser.peek() {
+        None => Ok(expr),
+        Some(tok) => Err(ParseError::UnexpectedToken(String::from(tok))),
+    }
+}
+
+/// Drive the lexer over `source` and collect every significant token, dropping
+/// `Whitespace`. The collected tokens borrow `source`, so the parser that reads
+/// them must not outlive it.
+fn tokenize<'a>(source: &'a str) -> Vec<Token<'a>> {
+    struct Collector<'a>(Rc<RefCell<Vec<Token<'a>>>>);
+    impl<'a> Consumer<Token<'a>> for Collector<'a> {
+        fn accept(&mut self, tok: Token<'a>) {
+            if tok != Whitespace {
+                self.0.borrow_mut().push(tok);
+            }
+        }
+    }
+    struct Driver<'a> {
+        source: &'a str,
+        tokens: Rc<RefCell<Vec<Token<'a>>>>,
+    }
+    impl<'a> LexerConsumer<Collector<'a>> for Driver<'a> {
+        fn accept<L>(self, mut lex: L) where L: for<'b> ParseTo<&'b str, Collector<'a>> {
+            let mut collector = Collector(self.tokens);
+            lex.push_to(self.source, &mut collector);
+        }
+    }
+    let tokens = Rc::new(RefCell::new(Vec::new()));
+    lexer(Driver { source: source, tokens: tokens.clone() });
+    let collected = tokens.borrow().clone();
+    collected
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token<'a>],
+    pos: usize,
+    // Symbolic `$name` locals, in first-seen order; the index is the position.
+    locals: Vec<&'a str>,
+}
+
+impl<'a> Parser<'a> {
+
+    fn peek(&self) -> Option<Token<'a>> {
+        self.tokens.get(self.pos).cloned()
+    }
+
+    fn bump(&mut self) -> Result<Token<'a>, ParseError> {
+        let tok = self.peek().ok_or(ParseError::UnexpectedEof)?;
+        self.pos += 1;
+        Ok(tok)
+    }
+
+    fn expect(&mut self, expected: Token<'a>) -> Result<(), ParseError> {
+        let tok = self.bump()?;
+        if tok == expected {
+            Ok(())
+        } else {
+            Err(ParseError::UnexpectedToken(String::from(tok)))
+        }
+    }
+
+    fn expect_identifier(&mut self) -> Result<&'a str, ParseError> {
+        match self.bump()? {
+            Identifier(name) => Ok(name),
+            tok => Err(ParseError::UnexpectedToken(String::from(tok))),
+        }
+    }
+
+    /// Parse a parenthesised expression: `(` keyword operands `)`.
+    fn parse_expr(&mut self) -> Result<Expr, ParseError> {
+        self.expect(LParen)?;
+        let keyword = self.expect_identifier()?;
+        let expr = self.parse_form(keyword)?;
+        self.expect(RParen)?;
+        Ok(expr)
+    }
+
+    fn parse_form(&mut self, keyword: &'a str) -> Result<Expr, ParseError> {
+        // Typed instructions carry a `<typ>.<op>` keyword; untyped ones don't.
+        if let Some(dot) = keyword.find('.') {
+            let typ = parse_typ(&keyword[..dot])?;
+            return self.parse_typed(typ, &keyword[dot + 1..], keyword);
+        }
+        match keyword {
+            "get_local" => {
+                let var = self.parse_var()?;
+                Ok(GetLocalExpr(var))
+            },
+            "set_local" => {
+                let var = self.parse_var()?;
+                let value = self.parse_expr()?;
+                Ok(SetLocalExpr(var, Box::new(value)))
+            },
+            "grow_memory" => Ok(GrowMemoryExpr(Box::new(self.parse_expr()?))),
+            "nop" => Ok(NopExpr),
+            "block" => Ok(BlockExpr(self.parse_body()?)),
+            "loop" => Ok(LoopExpr(self.parse_body()?)),
+            "br" => Ok(BrExpr(self.parse_index()?)),
+            "br_if" => {
+                let depth = self.parse_index()?;
+                let cond = self.parse_expr()?;
+                Ok(BrIfExpr(depth, Box::new(cond)))
+            },
+            "return" => Ok(ReturnExpr(Box::new(self.parse_expr()?))),
+            "if" => self.parse_if(),
+            _ => Err(ParseError::UnknownKeyword(String::from(keyword))),
+        }
+    }
+
+    fn parse_typed(&mut self, typ: Typ, op: &str, keyword: &'a str) -> Result<Expr, ParseError> {
+        if op == "const" {
+            return Ok(ConstExpr(self.parse_const(typ)?));
+        }
+        if op == "load" {
+            return Ok(LoadExpr(typ, Box::new(self.parse_expr()?)));
+        }
+        if op == "store" {
+            let addr = self.parse_expr()?;
+            let value = self.parse_expr()?;
+            return Ok(StoreExpr(typ, Box::new(addr), Box::new(value)));
+        }
+        if let Some(op) = binop(op) {
+            let lhs = self.parse_expr()?;
+            let rhs = self.parse_expr()?;
+            return Ok(BinOpExpr(typ, op, Box::new(lhs), Box::new(rhs)));
+        }
+        if let Some(op) = unop(op) {
+            return Ok(UnaryOpExpr(typ, op, Box::new(self.parse_expr()?)));
+        }
+        Err(ParseError::UnknownKeyword(String::from(keyword)))
+    }
+
+    /// `(if <cond> (then <body>) [(else <body>)])`
+    fn parse_if(&mut self) -> Result<Expr, ParseError> {
+        let cond = self.parse_expr()?;
+        let true_branch = self.parse_clause("then")?;
+        if self.peek() == Some(LParen) {
+            let false_branch = self.parse_clause("else")?;
+            Ok(IfThenElseExpr(Box::new(cond), Box::new(true_branch), Box::new(false_branch)))
+        } else {
+            Ok(IfThenExpr(Box::new(cond), Box::new(true_branch)))
+        }
+    }
+
+    /// Parse a `(then …)`/`(else …)` clause, collapsing a single-expression body
+    /// to that expression and a multi-expression body to a `block`.
+    fn parse_clause(&mut self, keyword: &str) -> Result<Expr, ParseError> {
+        self.expect(LParen)?;
+        let head = self.expect_identifier()?;
+        if head != keyword {
+            return Err(ParseError::UnexpectedToken(String::from(head)));
+        }
+        let mut body = self.parse_body()?;
+        self.expect(RParen)?;
+        if body.len() == 1 {
+            Ok(body.pop().unwrap())
+        } else {
+            Ok(BlockExpr(body))
+        }
+    }
+
+    /// Parse a run of nested expressions up to the closing `)`.
+    fn parse_body(&mut self) -> Result<Vec<Expr>, ParseError> {
+        let mut body = Vec::new();
+        while self.peek() == Some(LParen) {
+            body.push(self.parse_expr()?);
+        }
+        Ok(body)
+    }
+
+    /// Parse a local variable reference, either a positional index or a symbolic
+    /// `$name` resolved to its first-seen index.
+    fn parse_var(&mut self) -> Result<Var, ParseError> {
+        match self.bump()? {
+            Number(text) => Ok(Var { position: parse_usize(text)? }),
+            Identifier(name) if name.starts_with('$') => Ok(Var { position: self.resolve_local(name) }),
+            tok => Err(ParseError::UnexpectedToken(String::from(tok))),
+        }
+    }
+
+    fn resolve_local(&mut self, name: &'a str) -> usize {
+        match self.locals.iter().position(|known| *known == name) {
+            Some(position) => position,
+            None => {
+                self.locals.push(name);
+                self.locals.len() - 1
+            },
+        }
+    }
+
+    /// Parse a branch label/immediate index.
+    fn parse_index(&mut self) -> Result<u32, ParseError> {
+        match self.bump()? {
+            Number(text) => Ok(parse_usize(text)? as u32),
+            tok => Err(ParseError::UnexpectedToken(String::from(tok))),
+        }
+    }
+
+    fn parse_const(&mut self, typ: Typ) -> Result<Const, ParseError> {
+        let text = match self.bump()? {
+            Number(text) => text,
+            tok => return Err(ParseError::UnexpectedToken(String::from(tok))),
+        };
+        match typ {
+            I32 => Ok(I32Const(parse_u32(text)?)),
+            I64 => Ok(I64Const(parse_u64(text)?)),
+            F32 => Ok(F32Const(parse_float(text)? as f32)),
+            F64 => Ok(F64Const(parse_float(text)?)),
+        }
+    }
+
+}
+
+fn parse_typ(text: &str) -> Result<Typ, ParseError> {
+    match text {
+        "i32" => Ok(I32),
+        "i64" => Ok(I64),
+        "f32" => Ok(F32),
+        "f64" => Ok(F64),
+        _ => Err(ParseError::UnknownKeyword(String::from(text))),
+    }
+}
+
+fn binop(op: &str) -> Option<BinOp> {
+    Some(match op {
+        "add" => Add,
+        "sub" => Sub,
+        "mul" => Mul,
+        "div_u" => DivU,
+        "div_s" => DivS,
+        "rem_u" => RemU,
+        "rem_s" => RemS,
+        "and" => And,
+        "or" => Or,
+        "xor" => Xor,
+        "shl" => Shl,
+        "shr_u" => ShrU,
+        "shr_s" => ShrS,
+        "rotl" => RotL,
+        "rotr" => RotR,
+        "eq" => Eq,
+        "ne" => Ne,
+        "lt_s" => LtS,
+        "lt_u" => LtU,
+        "gt_s" => GtS,
+        "gt_u" => GtU,
+        "le_s" => LeS,
+        "le_u" => LeU,
+        "ge_s" => GeS,
+        "ge_u" => GeU,
+        _ => return None,
+    })
+}
+
+fn unop(op: &str) -> Option<UnaryOp> {
+    Some(match op {
+        "clz" => Clz,
+        "ctz" => Ctz,
+        "popcnt" => Popcnt,
+        "eqz" => Eqz,
+        _ => return None,
+    })
+}
+
+/// Parse an integer literal into its unsigned bit pattern, honouring a `0x`
+/// hexadecimal prefix and an optional leading sign. A negative literal such as
+/// `(i32.const -1)` is parsed as a magnitude and wrapped into two's-complement,
+/// matching how WAT writes signed constants.
+fn parse_u64(text: &str) -> Result<u64, ParseError> {
+    let (negative, body) = split_sign(text);
+    let magnitude = if body.starts_with("0x") || body.starts_with("0X") {
+        u64::from_str_radix(&body[2..], 16)
+    } else {
+        body.parse::<u64>()
+    };
+    magnitude.map(|m| if negative { m.wrapping_neg() } else { m })
+             .map_err(|_| ParseError::MalformedNumber(String::from(text)))
+}
+
+fn parse_u32(text: &str) -> Result<u32, ParseError> {
+    let (negative, body) = split_sign(text);
+    let magnitude = if body.starts_with("0x") || body.starts_with("0X") {
+        u32::from_str_radix(&body[2..], 16)
+    } else {
+        body.parse::<u32>()
+    };
+    magnitude.map(|m| if negative { m.wrapping_neg() } else { m })
+             .map_err(|_| ParseError::MalformedNumber(String::from(text)))
+}
+
+/// Split an optional leading `+`/`-` sign from a numeric literal.
+fn split_sign(text: &str) -> (bool, &str) {
+    if text.starts_with('-') {
+        (true, &text[1..])
+    } else if text.starts_with('+') {
+        (false, &text[1..])
+    } else {
+        (false, text)
+    }
+}
+
+fn parse_usize(text: &str) -> Result<usize, ParseError> {
+    text.parse::<usize>().map_err(|_| ParseError::MalformedNumber(String::from(text)))
+}
+
+fn parse_float(text: &str) -> Result<f64, ParseError> {
+    text.parse::<f64>().map_err(|_| ParseError::MalformedNumber(String::from(text)))
+}
+
+#[test]
+fn test_parse_negative_const() {
+    // `-1` is a valid `i32.const`; it parses as the two's-complement pattern.
+    match parse("(i32.const -1)") {
+        Ok(ConstExpr(I32Const(value))) => assert_eq!(value, 0xffff_ffff),
+        _ => panic!("(i32.const -1) should parse"),
+    }
+}
+
+#[test]
+fn test_parse_binop() {
+    match parse("(i32.add (i32.const 1) (i32.const 2))") {
+        Ok(BinOpExpr(I32, Add, lhs, rhs)) => match (*lhs, *rhs) {
+            (ConstExpr(I32Const(a)), ConstExpr(I32Const(b))) => assert_eq!((a, b), (1, 2)),
+            _ => panic!("operands should be i32 constants"),
+        },
+        _ => panic!("(i32.add …) should parse to a BinOpExpr"),
+    }
+}
+
+#[test]
+fn test_parse_symbolic_local() {
+    // A `$name` reference resolves to a positional index.
+    match parse("(get_local $x)") {
+        Ok(GetLocalExpr(var)) => assert_eq!(var.position, 0),
+        _ => panic!("(get_local $x) should parse"),
+    }
+}
+
+#[test]
+fn test_parse_unknown_keyword() {
+    assert_eq!(parse("(bogus)"), Err(ParseError::UnknownKeyword(String::from("bogus"))));
+}
@@ -0,0 +1,298 @@
+//! Decoder for the WebAssembly binary format (`.wasm`).
+//!
+//! The interpreter can already consume textual input; this module gives it a
+//! binary execution path. A `.wasm` module opens with the magic `\0asm` and a
+//! version word, followed by length-prefixed sections. Each function body in
+//! the code section is a stack-machine opcode stream, which we fold back into a
+//! `wasm_ast::Expr` tree by keeping an operand stack of partially-built
+//! expressions: a `const` pushes a leaf, and a binary opcode pops its two
+//! operands and pushes the combined node.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use wasm_ast::{BinOp, Expr, UnaryOp, Var};
+use wasm_ast::BinOp::{Add, And, DivS, DivU, Eq, GeS, GeU, GtS, GtU, LeS, LeU, LtS, LtU};
+use wasm_ast::BinOp::{Mul, Ne, Or, RemS, RemU, RotL, RotR, Shl, ShrS, ShrU, Sub, Xor};
+use wasm_ast::Const::{I32Const, I64Const};
+use wasm_ast::Expr::{BinOpExpr, ConstExpr, GetLocalExpr, LoadExpr, SetLocalExpr, StoreExpr, UnaryOpExpr};
+use wasm_ast::Typ::I32;
+use wasm_ast::UnaryOp::{Clz, Ctz, Eqz, Popcnt};
+
+/// A failure encountered while decoding a binary module.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DecodeError {
+    /// The stream ended before the value being decoded was complete.
+    UnexpectedEof,
+    /// The leading four bytes were not the `\0asm` magic.
+    BadMagic,
+    /// The version word was not the supported value.
+    BadVersion(u32),
+    /// An opcode in a function body is not recognised.
+    UnknownOpcode(u8),
+    /// An operator found fewer operands on the stack than it needed.
+    StackUnderflow,
+    /// A LEB128 varint ran past ten bytes, the most a 64-bit value needs. 32-bit
+    /// varints are read with the same bound.
+    MalformedVarint,
+}
+
+/// Decode a whole module, returning one expression per function body in the
+/// code section.
+pub fn decode_module(bytes: &[u8]) -> Result<Vec<Expr>, DecodeError> {
+    let mut decoder = Decoder { bytes: bytes, pos: 0 };
+    decoder.module()
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+
+    fn read_u8(&mut self) -> Result<u8, DecodeError> {
+        let byte = *self.bytes.get(self.pos).ok_or(DecodeError::UnexpectedEof)?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        let end = self.pos.checked_add(len).ok_or(DecodeError::UnexpectedEof)?;
+        if end > self.bytes.len() {
+            return Err(DecodeError::UnexpectedEof);
+        }
+        let slice = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    /// Read an unsigned LEB128 varint: accumulate the low 7 bits of each byte,
+    /// shifting left by 7 each step, until a byte with a clear high bit. A
+    /// 64-bit value is at most ten groups of 7 bits, so a longer run would
+    /// overflow the shift and is rejected as a `MalformedVarint`.
+    fn read_var_u64(&mut self) -> Result<u64, DecodeError> {
+        let mut result = 0u64;
+        let mut shift = 0;
+        loop {
+            if shift >= 64 {
+                return Err(DecodeError::MalformedVarint);
+            }
+            let byte = self.read_u8()?;
+            result |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(result);
+            }
+            shift += 7;
+        }
+    }
+
+    /// Read a signed LEB128 varint: as for the unsigned case, but sign-extend
+    /// the result when the second-highest bit (`0x40`) of the final byte is set.
+    /// The same ten-byte bound guards against a shift overflow.
+    fn read_var_i64(&mut self) -> Result<i64, DecodeError> {
+        let mut result = 0i64;
+        let mut shift = 0;
+        loop {
+            if shift >= 64 {
+                return Err(DecodeError::MalformedVarint);
+            }
+            let byte = self.read_u8()?;
+            result |= ((byte & 0x7f) as i64) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && (byte & 0x40) != 0 {
+                    result |= !0i64 << shift;
+                }
+                return Ok(result);
+            }
+        }
+    }
+
+    fn read_var_u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(self.read_var_u64()? as u32)
+    }
+
+    fn read_var_i32(&mut self) -> Result<i32, DecodeError> {
+        Ok(self.read_var_i64()? as i32)
+    }
+
+    fn module(&mut self) -> Result<Vec<Expr>, DecodeError> {
+        if self.read_bytes(4)? != b"\0asm" {
+            return Err(DecodeError::BadMagic);
+        }
+        let version = LittleEndian::read_u32(self.read_bytes(4)?);
+        if version != 1 {
+            return Err(DecodeError::BadVersion(version));
+        }
+        let mut bodies = Vec::new();
+        while self.pos < self.bytes.len() {
+            let id = self.read_u8()?;
+            let size = self.read_var_u32()? as usize;
+            let payload = self.read_bytes(size)?;
+            // The type, function and memory sections describe the module's
+            // signatures and layout; only the code section carries the bodies
+            // we lower to `Expr`.
+            if id == SECTION_CODE {
+                let mut section = Decoder { bytes: payload, pos: 0 };
+                let count = section.read_var_u32()?;
+                for _ in 0..count {
+                    bodies.push(section.function_body()?);
+                }
+            }
+        }
+        Ok(bodies)
+    }
+
+    /// Decode one entry of the code section into an expression tree.
+    fn function_body(&mut self) -> Result<Expr, DecodeError> {
+        let size = self.read_var_u32()? as usize;
+        let body = self.read_bytes(size)?;
+        let mut decoder = Decoder { bytes: body, pos: 0 };
+        // Local declarations: a run of (count, type) pairs we skip over.
+        let local_groups = decoder.read_var_u32()?;
+        for _ in 0..local_groups {
+            decoder.read_var_u32()?;
+            decoder.read_u8()?;
+        }
+        decoder.opcodes()
+    }
+
+    fn opcodes(&mut self) -> Result<Expr, DecodeError> {
+        let mut stack: Vec<Expr> = Vec::new();
+        while self.pos < self.bytes.len() {
+            let opcode = self.read_u8()?;
+            match opcode {
+                OP_END => break,
+                OP_I32_CONST => {
+                    let value = self.read_var_i32()?;
+                    stack.push(ConstExpr(I32Const(value as u32)));
+                },
+                OP_I64_CONST => {
+                    let value = self.read_var_i64()?;
+                    stack.push(ConstExpr(I64Const(value as u64)));
+                },
+                OP_GET_LOCAL => {
+                    let index = self.read_var_u32()? as usize;
+                    stack.push(GetLocalExpr(Var { position: index }));
+                },
+                OP_SET_LOCAL => {
+                    let index = self.read_var_u32()? as usize;
+                    let value = pop(&mut stack)?;
+                    stack.push(SetLocalExpr(Var { position: index }, Box::new(value)));
+                },
+                OP_I32_LOAD => {
+                    // The `align` and `offset` immediates are not modelled by
+                    // `LoadExpr`, so both are read and discarded.
+                    self.read_var_u32()?;
+                    self.read_var_u32()?;
+                    let addr = pop(&mut stack)?;
+                    stack.push(LoadExpr(I32, Box::new(addr)));
+                },
+                OP_I32_STORE => {
+                    self.read_var_u32()?;
+                    self.read_var_u32()?;
+                    let value = pop(&mut stack)?;
+                    let addr = pop(&mut stack)?;
+                    stack.push(StoreExpr(I32, Box::new(addr), Box::new(value)));
+                },
+                _ => {
+                    if let Some(op) = i32_binop(opcode) {
+                        let rhs = pop(&mut stack)?;
+                        let lhs = pop(&mut stack)?;
+                        stack.push(BinOpExpr(I32, op, Box::new(lhs), Box::new(rhs)));
+                    } else if let Some(op) = i32_unop(opcode) {
+                        let arg = pop(&mut stack)?;
+                        stack.push(UnaryOpExpr(I32, op, Box::new(arg)));
+                    } else {
+                        return Err(DecodeError::UnknownOpcode(opcode));
+                    }
+                },
+            }
+        }
+        pop(&mut stack)
+    }
+
+}
+
+fn pop(stack: &mut Vec<Expr>) -> Result<Expr, DecodeError> {
+    stack.pop().ok_or(DecodeError::StackUnderflow)
+}
+
+const SECTION_CODE: u8 = 0x0a;
+
+const OP_END: u8 = 0x0b;
+const OP_GET_LOCAL: u8 = 0x20;
+const OP_SET_LOCAL: u8 = 0x21;
+const OP_I32_LOAD: u8 = 0x28;
+const OP_I32_STORE: u8 = 0x36;
+const OP_I32_CONST: u8 = 0x41;
+const OP_I64_CONST: u8 = 0x42;
+
+fn i32_binop(opcode: u8) -> Option<BinOp> {
+    Some(match opcode {
+        0x46 => Eq,
+        0x47 => Ne,
+        0x48 => LtS,
+        0x49 => LtU,
+        0x4a => GtS,
+        0x4b => GtU,
+        0x4c => LeS,
+        0x4d => LeU,
+        0x4e => GeS,
+        0x4f => GeU,
+        0x6a => Add,
+        0x6b => Sub,
+        0x6c => Mul,
+        0x6d => DivS,
+        0x6e => DivU,
+        0x6f => RemS,
+        0x70 => RemU,
+        0x71 => And,
+        0x72 => Or,
+        0x73 => Xor,
+        0x74 => Shl,
+        0x75 => ShrS,
+        0x76 => ShrU,
+        0x77 => RotL,
+        0x78 => RotR,
+        _ => return None,
+    })
+}
+
+fn i32_unop(opcode: u8) -> Option<UnaryOp> {
+    Some(match opcode {
+        0x45 => Eqz,
+        0x67 => Clz,
+        0x68 => Ctz,
+        0x69 => Popcnt,
+        _ => return None,
+    })
+}
+
+#[test]
+fn test_read_var_u64() {
+    // The canonical LEB128 example: 624485 encodes as E5 8E 26.
+    let mut decoder = Decoder { bytes: &[0xE5, 0x8E, 0x26], pos: 0 };
+    assert_eq!(decoder.read_var_u64(), Ok(624485));
+}
+
+#[test]
+fn test_read_var_i64_negative() {
+    // -624485 encodes as 9B F1 59, sign-extended from the final group.
+    let mut decoder = Decoder { bytes: &[0x9B, 0xF1, 0x59], pos: 0 };
+    assert_eq!(decoder.read_var_i64(), Ok(-624485));
+}
+
+#[test]
+fn test_read_var_u64_overflow() {
+    // Ten continuation bytes shift past 64 bits; reject rather than overflow.
+    let mut decoder = Decoder { bytes: &[0x80; 10], pos: 0 };
+    assert_eq!(decoder.read_var_u64(), Err(DecodeError::MalformedVarint));
+}
+
+#[test]
+fn test_read_var_truncated() {
+    // A dangling continuation bit with no following byte is an EOF, not a panic.
+    let mut decoder = Decoder { bytes: &[0x80], pos: 0 };
+    assert_eq!(decoder.read_var_u64(), Err(DecodeError::UnexpectedEof));
+}
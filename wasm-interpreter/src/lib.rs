@@ -1,79 +1,186 @@
 extern crate byteorder;
 extern crate wasm_ast;
 
+pub mod decoder;
+
 use byteorder::{ByteOrder, LittleEndian};
 
 use std::iter::repeat;
 use std::default::Default;
 
-use wasm_ast::{BinOp, Expr, UnaryOp};
+use wasm_ast::{BinOp, Expr, Typ, UnaryOp};
 use wasm_ast::BinOp::{Add, And, DivS, DivU, Eq, GeS, GeU, GtS, GtU, LeS, LeU, LtS, LtU};
 use wasm_ast::BinOp::{Mul, Ne, Or, RemS, RemU, RotL, RotR, Shl, ShrS, ShrU, Sub, Xor};
 use wasm_ast::Const::{F32Const, F64Const, I32Const, I64Const};
-use wasm_ast::Expr::{BinOpExpr, ConstExpr, GetLocalExpr, GrowMemoryExpr, IfThenExpr, IfThenElseExpr, LoadExpr, NopExpr, SetLocalExpr, StoreExpr, UnaryOpExpr};
+use wasm_ast::Expr::{BinOpExpr, BlockExpr, BrExpr, BrIfExpr, ConstExpr, GetLocalExpr, GrowMemoryExpr, IfThenExpr, IfThenElseExpr, LoadExpr, LoopExpr, NopExpr, ReturnExpr, SetLocalExpr, StoreExpr, UnaryOpExpr};
 use wasm_ast::Typ::{F32, F64, I32, I64};
 use wasm_ast::UnaryOp::{Clz, Ctz, Popcnt, Eqz};
 
+/// A recoverable fault raised while interpreting an expression. Returning a
+/// `Trap` rather than panicking lets an embedder catch the fault instead of
+/// aborting the host process.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Trap {
+    OutOfBoundsMemoryAccess { addr: u32, len: usize },
+    DivideByZero,
+    IntegerOverflow,
+    Unreachable,
+    TypeError,
+}
+
+use self::Trap::{DivideByZero, IntegerOverflow, OutOfBoundsMemoryAccess, TypeError};
+
+/// An expression the flat bytecode VM cannot lower. `compile` returns this
+/// rather than panicking so an embedder can fall back to the tree-walker.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Unsupported {
+    /// `grow_memory` resizes the heap, which the flat VM does not model.
+    GrowMemory,
+    /// Structured control flow (`block`/`loop`/`br`/`br_if`/`return`) needs
+    /// label back-patching over the whole function body.
+    ControlFlow,
+}
+
+/// The control signal produced by evaluating an expression. `Next` carries an
+/// ordinary value; `Branch(n)` targets the `n`-th enclosing block or loop
+/// (`0` is the innermost), leaving a target block or restarting a target loop;
+/// `Return` unwinds all the way to the function boundary. Propagating this signal rather
+/// than a bare value lets `interpret_expr` model structured control flow
+/// (`block`/`loop`/`br`/`br_if`/`return`) without building an explicit CFG.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Flow<T> {
+    Next(T),
+    Branch(u32),
+    Return(T),
+}
+
+/// The number of bytes a value of the given type occupies in linear memory.
+fn byte_width(typ: Typ) -> usize {
+    match typ {
+        F32 | I32 => 4,
+        F64 | I64 => 8,
+    }
+}
+
+/// Check that `[addr, addr + byte_width(typ))` lies within the heap, returning
+/// an `OutOfBoundsMemoryAccess` trap otherwise.
+fn check_bounds(typ: Typ, addr: u32, heap_len: usize) -> Result<(), Trap> {
+    let len = byte_width(typ);
+    if (addr as usize).checked_add(len).map_or(false, |end| end <= heap_len) {
+        Ok(())
+    } else {
+        Err(OutOfBoundsMemoryAccess { addr: addr, len: len })
+    }
+}
+
 trait Interpreter<T> {
 
-    fn interpret_binop(&self, op: &BinOp, lhs: T, rhs: T) -> T;
+    fn interpret_binop(&self, op: &BinOp, lhs: T, rhs: T) -> Result<T, Trap>;
 
-    fn interpret_unop(&self, op: &UnaryOp, arg: T) -> T;
+    fn interpret_unop(&self, op: &UnaryOp, arg: T) -> Result<T, Trap>;
 
-    fn from_f32(&self, _: f32) -> T {
-        panic!("Type error.")
+    fn from_f32(&self, _: f32) -> Result<T, Trap> {
+        Err(TypeError)
     }
 
-    fn from_f64(&self, _: f64) -> T {
-        panic!("Type error.")
+    fn from_f64(&self, _: f64) -> Result<T, Trap> {
+        Err(TypeError)
     }
 
-    fn from_i32(&self, _: u32) -> T {
-        panic!("Type error.")
+    fn from_i32(&self, _: u32) -> Result<T, Trap> {
+        Err(TypeError)
     }
 
-    fn from_i64(&self, _: u64) -> T {
-        panic!("Type error.")
+    fn from_i64(&self, _: u64) -> Result<T, Trap> {
+        Err(TypeError)
     }
 
     fn from_raw(&self, _: u64) -> T;
 
     fn to_raw(&self, _: T) -> u64;
 
-    fn interpret_expr(&mut self, expr: &Expr, locals: &mut[u64], heap: &mut Vec<u8>) -> T
+    /// Re-type a non-`Next` control signal produced by a subexpression of type
+    /// `U` so it can keep unwinding through a context of type `T`. `Branch`
+    /// carries no value; a `Return` value is carried across the type boundary
+    /// through its raw word, so it keeps only the bits that `T` can hold (a
+    /// 64-bit value reframed into a 32-bit context loses its high half).
+    fn reframe<U>(&self, flow: Flow<U>) -> Flow<T>
+        where Self: Interpreter<U> + Interpreter<T>,
+              U: Copy, T: Copy,
+    {
+        match flow {
+            Flow::Next(value) => Flow::Next(self.from_raw(<Self as Interpreter<U>>::to_raw(self, value))),
+            Flow::Branch(depth) => Flow::Branch(depth),
+            Flow::Return(value) => Flow::Return(self.from_raw(<Self as Interpreter<U>>::to_raw(self, value))),
+        }
+    }
+
+    fn interpret_expr(&mut self, expr: &Expr, locals: &mut[u64], heap: &mut Vec<u8>) -> Result<Flow<T>, Trap>
         where Self: Interpreter<f32> + Interpreter<f64> + Interpreter<u32> + Interpreter<u64>,
               T: Copy + Default,
     {
-        // NOTE: currently only handling the control flow that can be dealt with in direct style.
-        // More sophisticated control flow will require a technique for handling a CFG,
-        // e.g. functional SSA.
+        // Evaluate a subexpression, unwrapping a `Next` value or short-circuiting
+        // the enclosing arm when the child wants to branch or return.
+        macro_rules! value {
+            ($sub:expr) => (
+                match self.interpret_expr($sub, locals, heap)? {
+                    Flow::Next(value) => value,
+                    flow => return Ok(self.reframe(flow)),
+                }
+            );
+        }
         match expr {
             &BinOpExpr(_, ref op, ref lhs, ref rhs) => {
-                let lhs = self.interpret_expr(lhs, locals, heap);
-                let rhs = self.interpret_expr(rhs, locals, heap);
-                self.interpret_binop(op, lhs, rhs)
-            },
-            &ConstExpr(F32Const(value)) => self.from_f32(value),
-            &ConstExpr(F64Const(value)) => self.from_f64(value),
-            &ConstExpr(I32Const(value)) => self.from_i32(value),
-            &ConstExpr(I64Const(value)) => self.from_i64(value),
-            &GetLocalExpr(ref var) => self.from_raw(locals[var.position]),
+                let lhs = value!(lhs);
+                let rhs = value!(rhs);
+                Ok(Flow::Next(self.interpret_binop(op, lhs, rhs)?))
+            },
+            &BlockExpr(ref body) => {
+                // A `br` targeting this block (`Branch(0)`) resumes after it;
+                // deeper branches and returns propagate outward. Normal
+                // completion yields the last child's value so a multi-expression
+                // block evaluates to its final expression.
+                let mut result = T::default();
+                for child in body.iter() {
+                    match self.interpret_expr(child, locals, heap)? {
+                        Flow::Next(value) => result = value,
+                        Flow::Branch(0) => return Ok(Flow::Next(T::default())),
+                        Flow::Branch(depth) => return Ok(Flow::Branch(depth - 1)),
+                        Flow::Return(value) => return Ok(Flow::Return(value)),
+                    }
+                }
+                Ok(Flow::Next(result))
+            },
+            &BrExpr(depth) => Ok(Flow::Branch(depth)),
+            &BrIfExpr(depth, ref cond) => {
+                let cond: u32 = value!(cond);
+                if cond == 0 {
+                    Ok(Flow::Next(T::default()))
+                } else {
+                    Ok(Flow::Branch(depth))
+                }
+            },
+            &ConstExpr(F32Const(value)) => Ok(Flow::Next(self.from_f32(value)?)),
+            &ConstExpr(F64Const(value)) => Ok(Flow::Next(self.from_f64(value)?)),
+            &ConstExpr(I32Const(value)) => Ok(Flow::Next(self.from_i32(value)?)),
+            &ConstExpr(I64Const(value)) => Ok(Flow::Next(self.from_i64(value)?)),
+            &GetLocalExpr(ref var) => Ok(Flow::Next(self.from_raw(locals[var.position]))),
             &GrowMemoryExpr(ref ext) => {
                 let result: u32 = heap.len() as u32;
-                let ext: u32 = self.interpret_expr(ext, locals, heap);
+                let ext: u32 = value!(ext);
                 heap.extend(repeat(0).take(ext as usize));
-                self.from_i32(result)
+                Ok(Flow::Next(self.from_i32(result)?))
             },
             &IfThenExpr(ref cond, ref true_branch) => {
-                let cond: u32 = self.interpret_expr(cond, locals, heap);
+                let cond: u32 = value!(cond);
                 if cond == 0 {
-                    T::default()
+                    Ok(Flow::Next(T::default()))
                 } else {
                     self.interpret_expr(true_branch, locals, heap)
                 }
             },
             &IfThenElseExpr(ref cond, ref true_branch, ref false_branch) => {
-                let cond: u32 = self.interpret_expr(cond, locals, heap);
+                let cond: u32 = value!(cond);
                 if cond == 0 {
                     self.interpret_expr(false_branch, locals, heap)
                 } else {
@@ -81,58 +188,86 @@ trait Interpreter<T> {
                 }
             },
             &LoadExpr(F32, ref addr) => {
-                let addr: u32 = self.interpret_expr(addr, locals, heap);
+                let addr: u32 = value!(addr);
+                check_bounds(F32, addr, heap.len())?;
                 let value: f32 = LittleEndian::read_f32(&heap[addr as usize..]);
-                self.from_f32(value)
+                Ok(Flow::Next(self.from_f32(value)?))
             },
             &LoadExpr(F64, ref addr) => {
-                let addr: u32 = self.interpret_expr(addr, locals, heap);
+                let addr: u32 = value!(addr);
+                check_bounds(F64, addr, heap.len())?;
                 let value: f64 = LittleEndian::read_f64(&heap[addr as usize..]);
-                self.from_f64(value)
+                Ok(Flow::Next(self.from_f64(value)?))
             },
             &LoadExpr(I32, ref addr) => {
-                let addr: u32 = self.interpret_expr(addr, locals, heap);
+                let addr: u32 = value!(addr);
+                check_bounds(I32, addr, heap.len())?;
                 let value: u32 = LittleEndian::read_u32(&heap[addr as usize..]);
-                self.from_i32(value)
+                Ok(Flow::Next(self.from_i32(value)?))
             },
             &LoadExpr(I64, ref addr) => {
-                let addr: u32 = self.interpret_expr(addr, locals, heap);
+                let addr: u32 = value!(addr);
+                check_bounds(I64, addr, heap.len())?;
                 let value: u64 = LittleEndian::read_u64(&heap[addr as usize..]);
-                self.from_i64(value)
+                Ok(Flow::Next(self.from_i64(value)?))
+            },
+            &LoopExpr(ref body) => {
+                // A `br` targeting this loop (`Branch(0)`) jumps back to the top
+                // of the body; deeper branches and returns propagate outward.
+                'restart: loop {
+                    let mut result = T::default();
+                    for child in body.iter() {
+                        match self.interpret_expr(child, locals, heap)? {
+                            Flow::Next(value) => result = value,
+                            Flow::Branch(0) => continue 'restart,
+                            Flow::Branch(depth) => return Ok(Flow::Branch(depth - 1)),
+                            Flow::Return(value) => return Ok(Flow::Return(value)),
+                        }
+                    }
+                    return Ok(Flow::Next(result));
+                }
+            },
+            &NopExpr => Ok(Flow::Next(T::default())),
+            &ReturnExpr(ref value) => {
+                let value = value!(value);
+                Ok(Flow::Return(value))
             },
-            &NopExpr => T::default(),
             &SetLocalExpr(ref var, ref value) => {
-                let value: T = self.interpret_expr(value, locals, heap);
+                let value: T = value!(value);
                 locals[var.position] = self.to_raw(value);
-                value
+                Ok(Flow::Next(value))
             },
             &StoreExpr(F32, ref addr, ref value) => {
-                let addr: u32 = self.interpret_expr(addr, locals, heap);
-                let value: f32 = self.interpret_expr(value, locals, heap);
+                let addr: u32 = value!(addr);
+                let value: f32 = value!(value);
+                check_bounds(F32, addr, heap.len())?;
                 LittleEndian::write_f32(&mut heap[addr as usize..], value);
-                self.from_f32(value)
+                Ok(Flow::Next(self.from_f32(value)?))
             },
             &StoreExpr(F64, ref addr, ref value) => {
-                let addr: u32 = self.interpret_expr(addr, locals, heap);
-                let value: f64 = self.interpret_expr(value, locals, heap);
+                let addr: u32 = value!(addr);
+                let value: f64 = value!(value);
+                check_bounds(F64, addr, heap.len())?;
                 LittleEndian::write_f64(&mut heap[addr as usize..], value);
-                self.from_f64(value)
+                Ok(Flow::Next(self.from_f64(value)?))
             },
             &StoreExpr(I32, ref addr, ref value) => {
-                let addr: u32 = self.interpret_expr(addr, locals, heap);
-                let value: u32 = self.interpret_expr(value, locals, heap);
+                let addr: u32 = value!(addr);
+                let value: u32 = value!(value);
+                check_bounds(I32, addr, heap.len())?;
                 LittleEndian::write_u32(&mut heap[addr as usize..], value);
-                self.from_i32(value)
+                Ok(Flow::Next(self.from_i32(value)?))
             },
             &StoreExpr(I64, ref addr, ref value) => {
-                let addr: u32 = self.interpret_expr(addr, locals, heap);
-                let value: u64 = self.interpret_expr(value, locals, heap);
+                let addr: u32 = value!(addr);
+                let value: u64 = value!(value);
+                check_bounds(I64, addr, heap.len())?;
                 LittleEndian::write_u64(&mut heap[addr as usize..], value);
-                self.from_i64(value)
+                Ok(Flow::Next(self.from_i64(value)?))
             },
             &UnaryOpExpr(_, ref op, ref arg) => {
-                let arg = self.interpret_expr(arg, locals, heap);
-                self.interpret_unop(op, arg)
+                let arg = value!(arg);
+                Ok(Flow::Next(self.interpret_unop(op, arg)?))
             },
        }
     }
@@ -143,12 +278,19 @@ pub struct Program;
 
 impl Interpreter<u32> for Program {
 
-    fn interpret_binop(&self, op: &BinOp, lhs: u32, rhs: u32) -> u32 {
-        match op {
+    fn interpret_binop(&self, op: &BinOp, lhs: u32, rhs: u32) -> Result<u32, Trap> {
+        Ok(match op {
             &Add => (lhs.wrapping_add(rhs)),
             &And => (lhs & rhs),
-            &DivU => (lhs / rhs),
-            &DivS => ((lhs as i32) / (rhs as i32)) as u32,
+            &DivU => if rhs == 0 { return Err(DivideByZero) } else { lhs / rhs },
+            &DivS => {
+                if rhs == 0 {
+                    return Err(DivideByZero);
+                } else if lhs == i32::min_value() as u32 && rhs == (-1i32) as u32 {
+                    return Err(IntegerOverflow);
+                }
+                ((lhs as i32) / (rhs as i32)) as u32
+            },
             &Eq => (lhs == rhs) as u32,
             &GeS => ((lhs as i32) >= (rhs as i32)) as u32,
             &GeU => (lhs >= rhs) as u32,
@@ -161,8 +303,18 @@ impl Interpreter<u32> for Program {
             &Mul => (lhs.wrapping_mul(rhs)),
             &Ne => (lhs != rhs) as u32,
             &Or => (lhs | rhs),
-            &RemS => ((lhs as i32) % (rhs as i32)) as u32,
-            &RemU => (lhs % rhs),
+            &RemS => {
+                if rhs == 0 {
+                    return Err(DivideByZero);
+                } else if lhs == i32::min_value() as u32 && rhs == (-1i32) as u32 {
+                    // `i32::MIN % -1` is mathematically zero but overflows the
+                    // hardware instruction; wasm defines the result as zero.
+                    0
+                } else {
+                    ((lhs as i32) % (rhs as i32)) as u32
+                }
+            },
+            &RemU => if rhs == 0 { return Err(DivideByZero) } else { lhs % rhs },
             &RotL => (lhs.rotate_left(rhs)),
             &RotR => (lhs.rotate_right(rhs)),
             &Shl => (lhs.wrapping_shl(rhs)),
@@ -170,20 +322,20 @@ impl Interpreter<u32> for Program {
             &ShrU => (lhs.wrapping_shr(rhs)),
             &Sub => (lhs.wrapping_sub(rhs)),
             &Xor => (lhs ^ rhs),
-        }
+        })
     }
 
-    fn interpret_unop(&self, op: &UnaryOp, arg: u32) -> u32 {
-        match op {
+    fn interpret_unop(&self, op: &UnaryOp, arg: u32) -> Result<u32, Trap> {
+        Ok(match op {
             &Clz => arg.leading_zeros(),
             &Ctz => arg.trailing_zeros(),
             &Popcnt => arg.count_ones(),
             &Eqz => (arg == 0) as u32,
-        }
+        })
     }
-    
-    fn from_i32(&self, value: u32) -> u32 {
-        value
+
+    fn from_i32(&self, value: u32) -> Result<u32, Trap> {
+        Ok(value)
     }
 
     fn from_raw(&self, value: u64) -> u32 {
@@ -195,3 +347,498 @@ impl Interpreter<u32> for Program {
     }
 
 }
+
+impl Interpreter<u64> for Program {
+
+    fn interpret_binop(&self, op: &BinOp, lhs: u64, rhs: u64) -> Result<u64, Trap> {
+        Ok(match op {
+            &Add => (lhs.wrapping_add(rhs)),
+            &And => (lhs & rhs),
+            &DivU => if rhs == 0 { return Err(DivideByZero) } else { lhs / rhs },
+            &DivS => {
+                if rhs == 0 {
+                    return Err(DivideByZero);
+                } else if lhs == i64::min_value() as u64 && rhs == (-1i64) as u64 {
+                    return Err(IntegerOverflow);
+                }
+                ((lhs as i64) / (rhs as i64)) as u64
+            },
+            &Eq => (lhs == rhs) as u64,
+            &GeS => ((lhs as i64) >= (rhs as i64)) as u64,
+            &GeU => (lhs >= rhs) as u64,
+            &GtS => ((lhs as i64) > (rhs as i64)) as u64,
+            &GtU => (lhs > rhs) as u64,
+            &LeS => ((lhs as i64) <= (rhs as i64)) as u64,
+            &LeU => (lhs <= rhs) as u64,
+            &LtS => ((lhs as i64) < (rhs as i64)) as u64,
+            &LtU => (lhs < rhs) as u64,
+            &Mul => (lhs.wrapping_mul(rhs)),
+            &Ne => (lhs != rhs) as u64,
+            &Or => (lhs | rhs),
+            &RemS => {
+                if rhs == 0 {
+                    return Err(DivideByZero);
+                } else if lhs == i64::min_value() as u64 && rhs == (-1i64) as u64 {
+                    0
+                } else {
+                    ((lhs as i64) % (rhs as i64)) as u64
+                }
+            },
+            &RemU => if rhs == 0 { return Err(DivideByZero) } else { lhs % rhs },
+            &RotL => (lhs.rotate_left(rhs as u32)),
+            &RotR => (lhs.rotate_right(rhs as u32)),
+            &Shl => (lhs.wrapping_shl(rhs as u32)),
+            &ShrS => ((lhs as i64).wrapping_shr(rhs as u32)) as u64,
+            &ShrU => (lhs.wrapping_shr(rhs as u32)),
+            &Sub => (lhs.wrapping_sub(rhs)),
+            &Xor => (lhs ^ rhs),
+        })
+    }
+
+    fn interpret_unop(&self, op: &UnaryOp, arg: u64) -> Result<u64, Trap> {
+        Ok(match op {
+            &Clz => arg.leading_zeros() as u64,
+            &Ctz => arg.trailing_zeros() as u64,
+            &Popcnt => arg.count_ones() as u64,
+            &Eqz => (arg == 0) as u64,
+        })
+    }
+
+    fn from_i64(&self, value: u64) -> Result<u64, Trap> {
+        Ok(value)
+    }
+
+    fn from_raw(&self, value: u64) -> u64 {
+        value
+    }
+
+    fn to_raw(&self, value: u64) -> u64 {
+        value
+    }
+
+}
+
+impl Interpreter<f32> for Program {
+
+    fn interpret_binop(&self, op: &BinOp, lhs: f32, rhs: f32) -> Result<f32, Trap> {
+        Ok(match op {
+            &Add => lhs + rhs,
+            &Sub => lhs - rhs,
+            &Mul => lhs * rhs,
+            &DivU => lhs / rhs,
+            &Eq => return self.from_i32((lhs == rhs) as u32),
+            &Ne => return self.from_i32((lhs != rhs) as u32),
+            &LtS | &LtU => return self.from_i32((lhs < rhs) as u32),
+            &GtS | &GtU => return self.from_i32((lhs > rhs) as u32),
+            &LeS | &LeU => return self.from_i32((lhs <= rhs) as u32),
+            &GeS | &GeU => return self.from_i32((lhs >= rhs) as u32),
+            // The remaining operators are integer-only.
+            &And | &Or | &Xor | &Shl | &ShrS | &ShrU | &RotL | &RotR | &RemS | &RemU | &DivS
+                => return Err(TypeError),
+        })
+    }
+
+    fn interpret_unop(&self, _op: &UnaryOp, _arg: f32) -> Result<f32, Trap> {
+        Err(TypeError)
+    }
+
+    fn from_f32(&self, value: f32) -> Result<f32, Trap> {
+        Ok(value)
+    }
+
+    fn from_i32(&self, value: u32) -> Result<f32, Trap> {
+        Ok(value as f32)
+    }
+
+    fn from_raw(&self, value: u64) -> f32 {
+        f32::from_bits(value as u32)
+    }
+
+    fn to_raw(&self, value: f32) -> u64 {
+        value.to_bits() as u64
+    }
+
+}
+
+impl Interpreter<f64> for Program {
+
+    fn interpret_binop(&self, op: &BinOp, lhs: f64, rhs: f64) -> Result<f64, Trap> {
+        Ok(match op {
+            &Add => lhs + rhs,
+            &Sub => lhs - rhs,
+            &Mul => lhs * rhs,
+            &DivU => lhs / rhs,
+            &Eq => return self.from_i32((lhs == rhs) as u32),
+            &Ne => return self.from_i32((lhs != rhs) as u32),
+            &LtS | &LtU => return self.from_i32((lhs < rhs) as u32),
+            &GtS | &GtU => return self.from_i32((lhs > rhs) as u32),
+            &LeS | &LeU => return self.from_i32((lhs <= rhs) as u32),
+            &GeS | &GeU => return self.from_i32((lhs >= rhs) as u32),
+            &And | &Or | &Xor | &Shl | &ShrS | &ShrU | &RotL | &RotR | &RemS | &RemU | &DivS
+                => return Err(TypeError),
+        })
+    }
+
+    fn interpret_unop(&self, _op: &UnaryOp, _arg: f64) -> Result<f64, Trap> {
+        Err(TypeError)
+    }
+
+    fn from_f64(&self, value: f64) -> Result<f64, Trap> {
+        Ok(value)
+    }
+
+    fn from_i32(&self, value: u32) -> Result<f64, Trap> {
+        Ok(value as f64)
+    }
+
+    fn from_raw(&self, value: u64) -> f64 {
+        f64::from_bits(value)
+    }
+
+    fn to_raw(&self, value: f64) -> u64 {
+        value.to_bits()
+    }
+
+}
+
+// A flat stack-bytecode for `Expr`.
+//
+// The tree-walking `interpret_expr` re-dispatches a `match` and re-borrows
+// `locals`/`heap` at every node. Lowering an `Expr` to a `Vec<Instr>` once and
+// then running a `pc` loop over an operand stack of raw `u64` words makes
+// repeated execution of the same function far cheaper: the dispatch cost is
+// paid at compile time and the operand traffic becomes a contiguous stack push
+// and pop rather than a recursive return.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Instr {
+    Const(u64),
+    Binop(BinOp, Typ),
+    Unop(UnaryOp, Typ),
+    GetLocal(usize),
+    SetLocal(usize),
+    Load(Typ),
+    Store(Typ),
+    JmpIfZero(usize),
+    Jmp(usize),
+}
+
+use self::Instr::{Binop, Const, GetLocal, Jmp, JmpIfZero, Load, SetLocal, Store, Unop};
+
+impl Program {
+
+    /// Lower an `Expr` into stack-bytecode by post-order traversal: operands are
+    /// emitted before the operator that consumes them, so execution is a single
+    /// left-to-right pass over the resulting `Vec<Instr>`. Constructs the flat
+    /// VM does not model are reported as an `Unsupported` error rather than
+    /// aborting the host process.
+    pub fn compile(expr: &Expr) -> Result<Vec<Instr>, Unsupported> {
+        let mut code = Vec::new();
+        Program::compile_into(expr, &mut code)?;
+        Ok(code)
+    }
+
+    fn compile_into(expr: &Expr, code: &mut Vec<Instr>) -> Result<(), Unsupported> {
+        match expr {
+            &BinOpExpr(typ, ref op, ref lhs, ref rhs) => {
+                Program::compile_into(lhs, code)?;
+                Program::compile_into(rhs, code)?;
+                code.push(Binop(op.clone(), typ));
+            },
+            &ConstExpr(F32Const(value)) => code.push(Const(value.to_bits() as u64)),
+            &ConstExpr(F64Const(value)) => code.push(Const(value.to_bits())),
+            &ConstExpr(I32Const(value)) => code.push(Const(value as u64)),
+            &ConstExpr(I64Const(value)) => code.push(Const(value)),
+            &GetLocalExpr(ref var) => code.push(GetLocal(var.position)),
+            &IfThenExpr(ref cond, ref true_branch) => {
+                // Compiled like `IfThenElseExpr` with an implicit `Const(0)`
+                // false arm, so both paths leave exactly one word on the stack
+                // and match the tree-walker's `Flow::Next(T::default())`:
+                // cond; JmpIfZero else; <true>; Jmp end; else: Const(0); end:
+                Program::compile_into(cond, code)?;
+                let else_patch = code.len();
+                code.push(JmpIfZero(0));
+                Program::compile_into(true_branch, code)?;
+                let end_patch = code.len();
+                code.push(Jmp(0));
+                let else_label = code.len();
+                code[else_patch] = JmpIfZero(else_label);
+                code.push(Const(0));
+                let end = code.len();
+                code[end_patch] = Jmp(end);
+            },
+            &IfThenElseExpr(ref cond, ref true_branch, ref false_branch) => {
+                // cond; JmpIfZero else; <true>; Jmp end; else: <false>; end:
+                Program::compile_into(cond, code)?;
+                let else_patch = code.len();
+                code.push(JmpIfZero(0));
+                Program::compile_into(true_branch, code)?;
+                let end_patch = code.len();
+                code.push(Jmp(0));
+                let else_label = code.len();
+                code[else_patch] = JmpIfZero(else_label);
+                Program::compile_into(false_branch, code)?;
+                let end = code.len();
+                code[end_patch] = Jmp(end);
+            },
+            &LoadExpr(typ, ref addr) => {
+                Program::compile_into(addr, code)?;
+                code.push(Load(typ));
+            },
+            &NopExpr => code.push(Const(0)),
+            &SetLocalExpr(ref var, ref value) => {
+                Program::compile_into(value, code)?;
+                code.push(SetLocal(var.position));
+            },
+            &StoreExpr(typ, ref addr, ref value) => {
+                Program::compile_into(addr, code)?;
+                Program::compile_into(value, code)?;
+                code.push(Store(typ));
+            },
+            &UnaryOpExpr(typ, ref op, ref arg) => {
+                Program::compile_into(arg, code)?;
+                code.push(Unop(op.clone(), typ));
+            },
+            &GrowMemoryExpr(_) => {
+                // `grow_memory` resizes the heap, which the flat VM does not
+                // model; it stays on the tree-walker.
+                return Err(Unsupported::GrowMemory);
+            },
+            &BlockExpr(_) | &LoopExpr(_) | &BrExpr(_) | &BrIfExpr(..) | &ReturnExpr(_) => {
+                // Structured control flow needs label back-patching over the
+                // whole function body; it stays on the tree-walker for now.
+                return Err(Unsupported::ControlFlow);
+            },
+        }
+        Ok(())
+    }
+
+    /// Execute stack-bytecode produced by [`compile`](Program::compile) and
+    /// return the word left on top of the operand stack, or `0` if it is empty.
+    pub fn run(&mut self, code: &[Instr], locals: &mut [u64], heap: &mut Vec<u8>) -> Result<u64, Trap> {
+        let mut stack: Vec<u64> = Vec::new();
+        let mut pc = 0;
+        while pc < code.len() {
+            match &code[pc] {
+                &Const(value) => stack.push(value),
+                &Binop(ref op, typ) => {
+                    let rhs = stack.pop().unwrap();
+                    let lhs = stack.pop().unwrap();
+                    let result = self.exec_binop(op, typ, lhs, rhs)?;
+                    stack.push(result);
+                },
+                &Unop(ref op, typ) => {
+                    let arg = stack.pop().unwrap();
+                    let result = self.exec_unop(op, typ, arg)?;
+                    stack.push(result);
+                },
+                &GetLocal(idx) => stack.push(locals[idx]),
+                &SetLocal(idx) => {
+                    let value = stack.pop().unwrap();
+                    locals[idx] = value;
+                    stack.push(value);
+                },
+                &Load(typ) => {
+                    let addr = stack.pop().unwrap() as u32;
+                    check_bounds(typ, addr, heap.len())?;
+                    let addr = addr as usize;
+                    stack.push(match typ {
+                        F32 | I32 => LittleEndian::read_u32(&heap[addr..]) as u64,
+                        F64 | I64 => LittleEndian::read_u64(&heap[addr..]),
+                    });
+                },
+                &Store(typ) => {
+                    let value = stack.pop().unwrap();
+                    let addr = stack.pop().unwrap() as u32;
+                    check_bounds(typ, addr, heap.len())?;
+                    let addr = addr as usize;
+                    match typ {
+                        F32 | I32 => LittleEndian::write_u32(&mut heap[addr..], value as u32),
+                        F64 | I64 => LittleEndian::write_u64(&mut heap[addr..], value),
+                    }
+                    stack.push(value);
+                },
+                &JmpIfZero(target) => {
+                    if stack.pop().unwrap() as u32 == 0 {
+                        pc = target;
+                        continue;
+                    }
+                },
+                &Jmp(target) => {
+                    pc = target;
+                    continue;
+                },
+            }
+            pc += 1;
+        }
+        Ok(stack.pop().unwrap_or(0))
+    }
+
+    /// Apply a binary operator to two raw words, preserving the per-type
+    /// semantics of `interpret_binop` (wrapping arithmetic, little-endian
+    /// memory). The words are narrowed to the operator's type, reused through
+    /// the existing trait impls, and widened back to a raw word.
+    fn exec_binop(&self, op: &BinOp, typ: Typ, lhs: u64, rhs: u64) -> Result<u64, Trap> {
+        match typ {
+            I32 => {
+                let result: u32 = self.interpret_binop(op, lhs as u32, rhs as u32)?;
+                Ok(result as u64)
+            },
+            I64 => self.interpret_binop(op, lhs, rhs),
+            F32 => {
+                let lhs = <Self as Interpreter<f32>>::from_raw(self, lhs);
+                let rhs = <Self as Interpreter<f32>>::from_raw(self, rhs);
+                let result: f32 = self.interpret_binop(op, lhs, rhs)?;
+                Ok(self.to_raw(result))
+            },
+            F64 => {
+                let lhs = <Self as Interpreter<f64>>::from_raw(self, lhs);
+                let rhs = <Self as Interpreter<f64>>::from_raw(self, rhs);
+                let result: f64 = self.interpret_binop(op, lhs, rhs)?;
+                Ok(self.to_raw(result))
+            },
+        }
+    }
+
+    fn exec_unop(&self, op: &UnaryOp, typ: Typ, arg: u64) -> Result<u64, Trap> {
+        match typ {
+            I32 => {
+                let result: u32 = self.interpret_unop(op, arg as u32)?;
+                Ok(result as u64)
+            },
+            I64 => self.interpret_unop(op, arg),
+            F32 => {
+                let arg = <Self as Interpreter<f32>>::from_raw(self, arg);
+                let result: f32 = self.interpret_unop(op, arg)?;
+                Ok(self.to_raw(result))
+            },
+            F64 => {
+                let arg = <Self as Interpreter<f64>>::from_raw(self, arg);
+                let result: f64 = self.interpret_unop(op, arg)?;
+                Ok(self.to_raw(result))
+            },
+        }
+    }
+
+}
+
+#[cfg(test)]
+use wasm_ast::Var;
+
+// Evaluate an expression as a `u32`, asserting it completes with a value rather
+// than branching or returning past the top level.
+#[cfg(test)]
+fn eval_u32(expr: &Expr) -> Result<u32, Trap> {
+    let mut program = Program;
+    let mut locals = [0u64; 4];
+    let mut heap: Vec<u8> = Vec::new();
+    match <Program as Interpreter<u32>>::interpret_expr(&mut program, expr, &mut locals, &mut heap)? {
+        Flow::Next(value) => Ok(value),
+        flow => panic!("expected a value, got {:?}", flow),
+    }
+}
+
+#[test]
+fn test_trap_divide_by_zero() {
+    let expr = BinOpExpr(I32, DivU, Box::new(ConstExpr(I32Const(1))), Box::new(ConstExpr(I32Const(0))));
+    assert_eq!(eval_u32(&expr), Err(DivideByZero));
+}
+
+#[test]
+fn test_trap_signed_overflow() {
+    let expr = BinOpExpr(I32, DivS,
+                         Box::new(ConstExpr(I32Const(i32::min_value() as u32))),
+                         Box::new(ConstExpr(I32Const((-1i32) as u32))));
+    assert_eq!(eval_u32(&expr), Err(IntegerOverflow));
+}
+
+#[test]
+fn test_trap_out_of_bounds_load() {
+    let expr = LoadExpr(I32, Box::new(ConstExpr(I32Const(100))));
+    assert_eq!(eval_u32(&expr), Err(OutOfBoundsMemoryAccess { addr: 100, len: 4 }));
+}
+
+#[test]
+fn test_trap_out_of_bounds_store() {
+    let expr = StoreExpr(I32, Box::new(ConstExpr(I32Const(100))), Box::new(ConstExpr(I32Const(7))));
+    assert_eq!(eval_u32(&expr), Err(OutOfBoundsMemoryAccess { addr: 100, len: 4 }));
+}
+
+// Compile an expression to bytecode and run it, returning the result word.
+#[cfg(test)]
+fn run_bytecode(expr: &Expr) -> Result<u64, Trap> {
+    let code = Program::compile(expr).expect("expression should compile");
+    let mut program = Program;
+    let mut locals = [0u64; 4];
+    let mut heap: Vec<u8> = Vec::new();
+    program.run(&code, &mut locals, &mut heap)
+}
+
+#[test]
+fn test_compile_run_roundtrip() {
+    let expr = BinOpExpr(I32, Add, Box::new(ConstExpr(I32Const(2))), Box::new(ConstExpr(I32Const(3))));
+    assert_eq!(run_bytecode(&expr), Ok(5));
+}
+
+#[test]
+fn test_compile_if_without_else_balances_stack() {
+    // A consumed `if`-without-`else` whose condition is false must still leave
+    // exactly one word on the stack (the implicit `Const(0)`), so the enclosing
+    // `add` finds both operands rather than underflowing.
+    let conditional = IfThenExpr(Box::new(ConstExpr(I32Const(0))), Box::new(ConstExpr(I32Const(7))));
+    let expr = BinOpExpr(I32, Add, Box::new(conditional), Box::new(ConstExpr(I32Const(10))));
+    assert_eq!(run_bytecode(&expr), Ok(10));
+}
+
+#[test]
+fn test_compile_refuses_control_flow() {
+    assert_eq!(Program::compile(&BlockExpr(Vec::new())), Err(Unsupported::ControlFlow));
+}
+
+#[test]
+fn test_compile_refuses_grow_memory() {
+    let expr = GrowMemoryExpr(Box::new(ConstExpr(I32Const(1))));
+    assert_eq!(Program::compile(&expr), Err(Unsupported::GrowMemory));
+}
+
+// Evaluate an expression, returning the raw control signal so tests can observe
+// `Branch`/`Return` as well as ordinary values.
+#[cfg(test)]
+fn flow_u32(expr: &Expr, locals: &mut [u64]) -> Result<Flow<u32>, Trap> {
+    let mut program = Program;
+    let mut heap: Vec<u8> = Vec::new();
+    <Program as Interpreter<u32>>::interpret_expr(&mut program, expr, locals, &mut heap)
+}
+
+#[test]
+fn test_block_yields_last_value() {
+    let body = vec![ConstExpr(I32Const(1)), ConstExpr(I32Const(2)), ConstExpr(I32Const(3))];
+    assert_eq!(flow_u32(&BlockExpr(body), &mut [0; 4]), Ok(Flow::Next(3)));
+}
+
+#[test]
+fn test_br_exits_block() {
+    // `br 0` leaves the block before the trailing constant runs.
+    let body = vec![BrExpr(0), ConstExpr(I32Const(5))];
+    assert_eq!(flow_u32(&BlockExpr(body), &mut [0; 4]), Ok(Flow::Next(0)));
+}
+
+#[test]
+fn test_return_propagates_past_block() {
+    let body = vec![ReturnExpr(Box::new(ConstExpr(I32Const(42)))), ConstExpr(I32Const(7))];
+    assert_eq!(flow_u32(&BlockExpr(body), &mut [0; 4]), Ok(Flow::Return(42)));
+}
+
+#[test]
+fn test_loop_counts_with_br_if() {
+    // loop { local0 = local0 + 1; br_if 0 (local0 < 3) } leaves local0 == 3.
+    let incr = SetLocalExpr(Var { position: 0 },
+                            Box::new(BinOpExpr(I32, Add,
+                                               Box::new(GetLocalExpr(Var { position: 0 })),
+                                               Box::new(ConstExpr(I32Const(1))))));
+    let again = BrIfExpr(0, Box::new(BinOpExpr(I32, LtU,
+                                               Box::new(GetLocalExpr(Var { position: 0 })),
+                                               Box::new(ConstExpr(I32Const(3))))));
+    let mut locals = [0u64; 4];
+    flow_u32(&LoopExpr(vec![incr, again]), &mut locals).unwrap();
+    assert_eq!(locals[0], 3);
+}